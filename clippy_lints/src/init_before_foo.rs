@@ -1,12 +1,12 @@
 use crate::utils::span_lint;
 use rustc::declare_lint_pass;
-use rustc::hir::def_id::DefId;
+use rustc::hir::def_id::{DefId, LOCAL_CRATE};
 use rustc::hir::Crate;
 use rustc::lint::{LateContext, LateLintPass, LintArray, LintPass};
 use rustc::mir;
 use rustc::ty;
-use rustc::ty::TyCtxt;
-use rustc_data_structures::fx::FxHashSet;
+use rustc::ty::{Instance, InstanceDef, TyCtxt};
+use rustc_data_structures::fx::FxHashMap;
 use rustc_index::bit_set::BitSet;
 use rustc_index::vec::Idx;
 use rustc_mir::dataflow::{do_dataflow, BitDenotation, BottomValue, DataflowResultsCursor, DebugFormatted, GenKillSet};
@@ -15,52 +15,312 @@ use syntax::source_map::Span;
 use syntax_pos::symbol::Symbol;
 
 declare_clippy_lint! {
-    ///Checks whether the init/foo API is used correctly
+    /// Checks that "must call `precondition` before `guarded`" API protocols, such as `init`
+    /// before `foo`, are honored.
     pub INIT_BEFORE_FOO,
     correctness,
-    "must call the `init` function before the `foo` function"
+    "a guarded function was called without its precondition function being called first"
 }
 
-declare_lint_pass!(Pass => [INIT_BEFORE_FOO]);
+declare_clippy_lint! {
+    /// A `rustc_peek`-style sanity check for the `SeenInit` dataflow analysis, for use in UI
+    /// tests only. Write `clippy_dataflow_peek_set(x)` (or `clippy_dataflow_peek_unset(x)`) at a
+    /// program point to assert that protocol 0's precondition has (or hasn't) been seen on every
+    /// path reaching that point; the lint fires if the assertion doesn't hold.
+    pub DATAFLOW_PEEK,
+    internal,
+    "sanity check asserting `init_before_foo` dataflow state at a program point, do not use"
+}
+
+declare_lint_pass!(Pass => [INIT_BEFORE_FOO, DATAFLOW_PEEK]);
+
+/// A single "must call `precondition` before any of `guarded`" typestate protocol, named by the
+/// diagnostic items of its functions.
+///
+/// Eventually `PROTOCOLS` should be populated from `clippy.toml` so downstream crates can
+/// describe their own init/use-style APIs (`lock`/`access`, `open`/`read`, `begin`/`commit`, ...)
+/// instead of only the `init`/`foo` example baked in here.
+struct Protocol {
+    precondition: &'static str,
+    guarded: &'static [&'static str],
+}
+
+static PROTOCOLS: &[Protocol] = &[Protocol {
+    precondition: "init",
+    guarded: &["foo"],
+}];
+
+/// Indexes a single protocol's bit within a `SeenInit` bitset.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+struct ProtocolIdx(usize);
+
+impl Idx for ProtocolIdx {
+    fn index(self) -> usize {
+        self.0
+    }
+    fn new(idx: usize) -> Self {
+        ProtocolIdx(idx)
+    }
+}
 
 impl<'a, 'tcx> LateLintPass<'a, 'tcx> for Pass {
     #[allow(clippy::too_many_lines)]
     fn check_crate(&mut self, cx: &LateContext<'a, 'tcx>, _: &'tcx Crate) {
-        // Only trigger the lint if this function has a main function
+        // Only trigger the lint if this crate has a main function
         if let Some(main_fn) = cx.tcx.get_diagnostic_item(Symbol::intern("check_main")) {
-            #[allow(clippy::default_trait_access)]
-            let mut call_stack: FxHashSet<DefId> = Default::default();
-            if let InitState::NeedsInit(span) = check_init(cx.tcx, main_fn, &mut call_stack) {
+            let summaries = resolve_summaries(cx.tcx, main_fn);
+            if let Some(InitState::NeedsInit(spans)) = summaries.get(&main_fn) {
                 span_lint(
                     cx,
                     INIT_BEFORE_FOO,
-                    span,
-                    "call to `foo` not preceded by call to `init`",
+                    spans.clone(),
+                    "call to a guarded function not preceded by a call to its precondition function",
                 );
             }
         }
+
+        check_dataflow_peeks(cx);
     }
 }
 
+/// The only protocol `clippy_dataflow_peek_set`/`clippy_dataflow_peek_unset` can assert on today
+/// -- i.e. `PROTOCOLS[0]`. There's no way yet to pin down the dataflow state of any other
+/// protocol; if `PROTOCOLS` grows past one entry, the peek functions need a way to name which
+/// protocol they're asserting on (e.g. an explicit index argument) before they're useful again.
+const PEEK_PROTOCOL: ProtocolIdx = ProtocolIdx(0);
+
+/// Finds every `clippy_dataflow_peek_set`/`clippy_dataflow_peek_unset` call in the crate and
+/// reports a `DATAFLOW_PEEK` lint wherever the asserted state doesn't match the `SeenInit`
+/// dataflow result at that point, mirroring the compiler's `rustc_peek` test harness.
+fn check_dataflow_peeks(cx: &LateContext<'_, '_>) {
+    let tcx = cx.tcx;
+    for &def_id in tcx.mir_keys(LOCAL_CRATE).iter() {
+        let def_id = def_id.to_def_id();
+        if !tcx.is_mir_available(def_id) {
+            continue;
+        }
+        let mir = tcx.optimized_mir(def_id);
+
+        let dead_unwinds = BitSet::new_empty(mir.basic_blocks().len());
+        let seen_init = do_dataflow(
+            tcx,
+            mir,
+            def_id,
+            &[],
+            &dead_unwinds,
+            SeenInit {
+                tcx,
+                mir,
+                summaries: Default::default(),
+            },
+            |_bd, _p| DebugFormatted::new(&"no id"),
+        );
+        let mut cursor = DataflowResultsCursor::new(seen_init, mir);
+
+        for (block, bbdata) in mir.basic_blocks().iter_enumerated() {
+            let terminator = bbdata.terminator();
+            let callee_id = match &terminator.kind {
+                mir::TerminatorKind::Call { func, .. } => match func.ty(&**mir, tcx).kind {
+                    ty::FnDef(callee_id, _) => callee_id,
+                    _ => continue,
+                },
+                _ => continue,
+            };
+
+            let expected = if tcx.is_diagnostic_item(Symbol::intern("clippy_dataflow_peek_set"), callee_id) {
+                true
+            } else if tcx.is_diagnostic_item(Symbol::intern("clippy_dataflow_peek_unset"), callee_id) {
+                false
+            } else {
+                continue;
+            };
+
+            let loc = mir::Location {
+                block,
+                statement_index: bbdata.statements.len(),
+            };
+            cursor.seek(loc);
+            let actual = cursor.contains(PEEK_PROTOCOL);
+            if actual != expected {
+                span_lint(
+                    cx,
+                    DATAFLOW_PEEK,
+                    terminator.source_info.span,
+                    &format!(
+                        "expected protocol {}'s precondition to be {} here, but it was {}",
+                        PEEK_PROTOCOL.0,
+                        if expected { "set" } else { "unset" },
+                        if actual { "set" } else { "unset" },
+                    ),
+                );
+            }
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
 enum InitState {
-    Init,
+    /// `def_id` is the precondition function of these protocols.
+    Precondition(Vec<ProtocolIdx>),
     NotInit,
     NeedsInit(Vec<Span>),
 }
 
-fn check_init(tcx: TyCtxt<'_>, def_id: DefId, call_stack: &mut FxHashSet<DefId>) -> InitState {
-    // Bail out on recursion (stack already contains a call to this function)
-    if !call_stack.insert(def_id) {
-        return InitState::NotInit;
+/// The protocols for which `def_id` is the precondition function.
+fn precondition_protocols(tcx: TyCtxt<'_>, def_id: DefId) -> Vec<ProtocolIdx> {
+    PROTOCOLS
+        .iter()
+        .enumerate()
+        .filter(|(_, protocol)| tcx.is_diagnostic_item(Symbol::intern(protocol.precondition), def_id))
+        .map(|(idx, _)| ProtocolIdx(idx))
+        .collect()
+}
+
+/// Every protocol (there may be more than one) that guards calling `def_id`.
+fn guarding_protocols(tcx: TyCtxt<'_>, def_id: DefId) -> Vec<ProtocolIdx> {
+    PROTOCOLS
+        .iter()
+        .enumerate()
+        .filter(|(_, protocol)| {
+            protocol
+                .guarded
+                .iter()
+                .any(|guarded| tcx.is_diagnostic_item(Symbol::intern(guarded), def_id))
+        })
+        .map(|(idx, _)| ProtocolIdx(idx))
+        .collect()
+}
+
+/// How to treat a call whose callee can't be resolved to a concrete `FnDef` even after
+/// `resolve_callee`'s devirtualization attempt (e.g. a genuine `dyn Trait` call through a
+/// vtable). `Conservative` assumes the worst, matching this lint's original behavior of treating
+/// every dynamic call as if it required every protocol's precondition; `Precise` instead treats
+/// the call as opaque and skips it, trading soundness for fewer false positives.
+///
+/// Eventually this should be a `clippy.toml` option alongside `PROTOCOLS`.
+enum Strictness {
+    Conservative,
+    Precise,
+}
+
+const STRICTNESS: Strictness = Strictness::Conservative;
+
+/// Resolves a call's callee operand to a concrete `DefId`, devirtualizing closures,
+/// function-generator bodies, function pointers, and monomorphizable trait-method calls instead
+/// of immediately giving up on anything that isn't already a literal `ty::FnDef`.
+///
+/// Returns `None` whenever the target can't be narrowed down to a concrete `InstanceDef::Item`:
+/// a `dyn Trait` call dispatched through a vtable, an unresolved generic, a call that resolves
+/// to a compiler-provided shim or intrinsic, or an untraceable function pointer.
+fn resolve_callee<'tcx>(tcx: TyCtxt<'tcx>, mir: &mir::Body<'tcx>, func: &mir::Operand<'tcx>) -> Option<DefId> {
+    match func.ty(mir, tcx).kind {
+        ty::FnDef(def_id, substs) => {
+            // Monomorphic trait-method calls (as opposed to `dyn Trait` calls) still show up as
+            // a `FnDef` for the trait method itself; `Instance::resolve` finds the concrete
+            // `impl` it actually dispatches to when `substs` are concrete enough to do so. A
+            // `dyn Trait` call resolves too, but to an `InstanceDef::Virtual` shim rather than a
+            // concrete `Item` -- mirroring the MIR inliner, we only trust `Item` resolutions and
+            // treat anything else (virtual dispatch, or a genuinely unresolved generic) as not
+            // narrowed down, so it falls through to the normal `Strictness` handling.
+            let param_env = tcx.param_env(mir.source.def_id());
+            match Instance::resolve(tcx, param_env, def_id, substs) {
+                Some(Instance { def: InstanceDef::Item(def_id), .. }) => Some(def_id),
+                _ => None,
+            }
+        },
+        ty::Closure(def_id, _) | ty::Generator(def_id, _, _) => Some(def_id),
+        ty::FnPtr(_) => resolve_fn_pointer(mir, func),
+        // Any other callee type; there's no single concrete target to resolve.
+        _ => None,
     }
-    let result = check_init_inner(tcx, def_id, call_stack);
-    call_stack.remove(&def_id);
-    result
 }
 
-fn check_init_inner(tcx: TyCtxt<'_>, def_id: DefId, call_stack: &mut FxHashSet<DefId>) -> InitState {
-    if tcx.is_diagnostic_item(Symbol::intern("init"), def_id) {
-        return InitState::Init;
+/// Follows a function-pointer operand back to the single assignment that produced it by
+/// reifying a concrete function item into a pointer, so that e.g. `let f: fn() = foo; f();` is
+/// treated the same as calling `foo` directly. Plain copies and moves between locals (such as
+/// the temporary holding `f` for the call) are followed back to that assignment.
+///
+/// Requires each local along the way to be assigned to exactly once in the whole body; without dominance
+/// information we can't tell which of several writes (e.g. behind an `if`) reaches this call, so
+/// a reassigned local bails out to `None` rather than guessing.
+fn resolve_fn_pointer<'tcx>(mir: &mir::Body<'tcx>, func: &mir::Operand<'tcx>) -> Option<DefId> {
+    let local = match func {
+        mir::Operand::Copy(place) | mir::Operand::Move(place) => place.as_local()?,
+        mir::Operand::Constant(_) => return None,
+    };
+
+    let mut assignments = mir
+        .basic_blocks()
+        .iter()
+        .flat_map(|bb| &bb.statements)
+        .filter_map(|stmt| match &stmt.kind {
+            mir::StatementKind::Assign(assign) if assign.0.as_local() == Some(local) => Some(&assign.1),
+            _ => None,
+        });
+
+    let rvalue = assignments.next()?;
+    if assignments.next().is_some() {
+        // Reassigned somewhere else in the body; don't guess which write reaches this call.
+        return None;
+    }
+    match rvalue {
+        mir::Rvalue::Cast(mir::CastKind::Pointer(_), operand, _) => match operand.constant()?.literal.ty.kind {
+            ty::FnDef(def_id, _) => Some(def_id),
+            _ => None,
+        },
+        mir::Rvalue::Use(operand) => resolve_fn_pointer(mir, operand),
+        _ => None,
+    }
+}
+
+/// Computes the crate-wide, memoized `InitState` summary for `root` and everything it
+/// transitively calls.
+///
+/// Rather than re-running the dataflow analysis for the same `DefId` at every call site (which
+/// is exponential on deep or diamond-shaped call graphs), this is a worklist over the call
+/// graph: each `DefId` gets a single cached summary, recursive cycles are seeded with an
+/// optimistic `NotInit` guess, and the whole cache is swept repeatedly until no summary
+/// changes, at which point it has reached a fixpoint.
+fn resolve_summaries(tcx: TyCtxt<'_>, root: DefId) -> FxHashMap<DefId, InitState> {
+    let mut cache: FxHashMap<DefId, InitState> = Default::default();
+    cache.insert(root, InitState::NotInit);
+
+    loop {
+        let mut changed = false;
+        let len_before_sweep = cache.len();
+        // Snapshot the keys: `check_init_inner` may discover new callees and seed them into
+        // `cache`, and we want those picked up on the *next* sweep rather than this one.
+        let def_ids: Vec<DefId> = cache.keys().copied().collect();
+        for def_id in def_ids {
+            let result = check_init_inner(tcx, def_id, &mut cache);
+            if cache.get(&def_id) != Some(&result) {
+                changed = true;
+            }
+            cache.insert(def_id, result);
+        }
+        // A callee discovered (and seeded with the `NotInit` guess) partway through this sweep
+        // hasn't been run through `check_init_inner` itself yet, even if every `DefId` that *was*
+        // swept this round kept its previous verdict. Force another sweep so newly-discovered
+        // callees actually get analyzed instead of being stuck at their seed forever.
+        if cache.len() != len_before_sweep {
+            changed = true;
+        }
+        if !changed {
+            return cache;
+        }
+    }
+}
+
+/// The memoized summary for `def_id`, seeding it with a `NotInit` fixpoint guess the first time
+/// it's discovered so the enclosing worklist in `resolve_summaries` picks it up next sweep.
+fn summary_for(def_id: DefId, cache: &mut FxHashMap<DefId, InitState>) -> InitState {
+    cache.entry(def_id).or_insert(InitState::NotInit).clone()
+}
+
+fn check_init_inner(tcx: TyCtxt<'_>, def_id: DefId, cache: &mut FxHashMap<DefId, InitState>) -> InitState {
+    let preconditions = precondition_protocols(tcx, def_id);
+    if !preconditions.is_empty() {
+        return InitState::Precondition(preconditions);
     }
 
     // MIR from other crates may not be available, so we won't be able to detect anything there
@@ -81,7 +341,10 @@ fn check_init_inner(tcx: TyCtxt<'_>, def_id: DefId, call_stack: &mut FxHashSet<D
         SeenInit {
             tcx,
             mir,
-            call_stack: call_stack.clone(),
+            // A snapshot of the cache as it stands *before* this sweep's pass over `def_id`; good
+            // enough to decide whether a callee is a protocol's precondition function without
+            // spawning another nested `do_dataflow` run.
+            summaries: cache.clone(),
         },
         |_bd, _p| DebugFormatted::new(&"no id"),
     );
@@ -89,87 +352,94 @@ fn check_init_inner(tcx: TyCtxt<'_>, def_id: DefId, call_stack: &mut FxHashSet<D
 
     for (block, bbdata) in mir.basic_blocks().iter_enumerated() {
         let terminator = bbdata.terminator();
-        let callee_id = match &terminator.kind {
-            mir::TerminatorKind::Call { func, .. } => match func.ty(&**mir, tcx).kind {
-                ty::FnDef(def_id, _) => def_id,
-                // Function pointer calls aren't implemented in this simple analyses, so we assume
-                // any dynamic call to require init to have been called.
-                _ => return InitState::NeedsInit(vec![terminator.source_info.span]),
-            },
+        let func = match &terminator.kind {
+            mir::TerminatorKind::Call { func, .. } => func,
             // We only care about function calls
             _ => continue,
         };
+        let callee_id = match resolve_callee(tcx, mir, func) {
+            Some(callee_id) => callee_id,
+            // Couldn't narrow down a genuinely dynamic call: fall back per `STRICTNESS`.
+            None => match STRICTNESS {
+                Strictness::Conservative => return InitState::NeedsInit(vec![terminator.source_info.span]),
+                Strictness::Precise => continue,
+            },
+        };
 
         let loc = mir::Location {
             block,
             statement_index: bbdata.statements.len(),
         };
-        // If init has not been called before reaching this source location,
-        // then we must report an error on all `foo` calls encountered
+        // If a guarded call is reached without one of its protocols' precondition having been
+        // seen on every incoming path, report it.
         cursor.seek(loc);
-        if !cursor.contains(NoIdx) && tcx.is_diagnostic_item(Symbol::intern("foo"), callee_id) {
-            return InitState::NeedsInit(vec![terminator.source_info.span]);
-        } else if let InitState::NeedsInit(mut span) = check_init(tcx, callee_id, call_stack) {
-            span.push(terminator.source_info.span);
-            return InitState::NeedsInit(span);
+        for protocol in guarding_protocols(tcx, callee_id) {
+            if !cursor.contains(protocol) {
+                return InitState::NeedsInit(vec![terminator.source_info.span]);
+            }
+        }
+        if let InitState::NeedsInit(mut spans) = summary_for(callee_id, cache) {
+            spans.push(terminator.source_info.span);
+            return InitState::NeedsInit(spans);
         }
     }
     InitState::NotInit
 }
 
-/// Determines whether `init` has been called at a specific point in the code
+/// Determines, for each protocol in `PROTOCOLS`, whether its precondition has been called at a
+/// specific point in the code.
 struct SeenInit<'a, 'tcx> {
     mir: &'a mir::Body<'tcx>,
     tcx: TyCtxt<'tcx>,
-    call_stack: FxHashSet<DefId>,
-}
-
-#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
-struct NoIdx;
-
-impl Idx for NoIdx {
-    fn index(self) -> usize {
-        0
-    }
-    fn new(_: usize) -> Self {
-        unimplemented!();
-    }
+    /// Snapshot of the crate-wide summary cache (see `resolve_summaries`), consulted instead of
+    /// recursing into the dataflow analysis for each callee.
+    summaries: FxHashMap<DefId, InitState>,
 }
 
 impl<'a, 'tcx> BitDenotation<'tcx> for SeenInit<'a, 'tcx> {
-    type Idx = NoIdx;
+    type Idx = ProtocolIdx;
     fn name() -> &'static str {
         "seen init"
     }
 
     fn bits_per_block(&self) -> usize {
-        1
+        PROTOCOLS.len()
     }
 
-    fn start_block_effect(&self, _on_entry: &mut BitSet<NoIdx>) {}
+    fn start_block_effect(&self, on_entry: &mut BitSet<ProtocolIdx>) {
+        // Every protocol's precondition is unseen at function entry.
+        on_entry.clear();
+    }
 
-    fn statement_effect(&self, _trans: &mut GenKillSet<NoIdx>, _loc: mir::Location) {}
+    fn statement_effect(&self, _trans: &mut GenKillSet<ProtocolIdx>, _loc: mir::Location) {}
 
-    fn terminator_effect(&self, trans: &mut GenKillSet<NoIdx>, loc: mir::Location) {
+    fn terminator_effect(&self, trans: &mut GenKillSet<ProtocolIdx>, loc: mir::Location) {
         let func = match &self.mir[loc.block].terminator().kind {
             mir::TerminatorKind::Call { func, .. } => func,
             // We only care about function calls
             _ => return,
         };
-        let callee_id = match func.ty(self.mir, self.tcx).kind {
-            ty::FnDef(id, _) => id,
-            // Function pointer calls aren't implemented in this simple analyses, so we assume
-            // any dynamic call to require init to have been called.
-            _ => return,
+        let callee_id = match resolve_callee(self.tcx, self.mir, func) {
+            Some(id) => id,
+            // A genuinely dynamic call can't be the literal precondition function, so there's
+            // nothing to `gen` here regardless of `STRICTNESS`.
+            None => return,
+        };
+        // Precondition membership never needs the full summary (it's a plain diagnostic-item
+        // check), but consult the cached summary first so a callee already known to be a
+        // precondition function doesn't even pay for that lookup.
+        let protocols = match self.summaries.get(&callee_id) {
+            Some(InitState::Precondition(protocols)) => protocols.clone(),
+            _ => precondition_protocols(self.tcx, callee_id),
         };
-        if let InitState::Init = check_init(self.tcx, callee_id, &mut self.call_stack.clone()) {
-            trans.gen(NoIdx);
+        for protocol in protocols {
+            trans.gen(protocol);
         }
     }
 
     fn propagate_call_return(
         &self,
-        _in_out: &mut BitSet<NoIdx>,
+        _in_out: &mut BitSet<ProtocolIdx>,
         _call_bb: mir::BasicBlock,
         _dest_bb: mir::BasicBlock,
         _dest_place: &mir::Place<'tcx>,
@@ -179,6 +449,7 @@ impl<'a, 'tcx> BitDenotation<'tcx> for SeenInit<'a, 'tcx> {
 }
 
 impl<'a, 'tcx> BottomValue for SeenInit<'a, 'tcx> {
-    /// bottom = not seen
-    const BOTTOM_VALUE: bool = false;
+    /// bottom = seen on every path so far (this is a "definitely seen" must-analysis, so the
+    /// join of two paths is their intersection: a bit only survives if both predecessors set it).
+    const BOTTOM_VALUE: bool = true;
 }
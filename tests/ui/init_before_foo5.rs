@@ -0,0 +1,16 @@
+#![feature(rustc_attrs)]
+
+#[rustc_diagnostic_item = "check_main"]
+fn main() {
+    // `init` is called through a function pointer rather than directly; this should be resolved
+    // back to `init` instead of being treated as an opaque dynamic call.
+    let f: fn() = init;
+    f();
+    foo();
+}
+
+#[rustc_diagnostic_item = "foo"]
+fn foo() {}
+
+#[rustc_diagnostic_item = "init"]
+fn init() {}
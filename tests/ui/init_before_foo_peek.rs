@@ -0,0 +1,16 @@
+#![feature(rustc_attrs)]
+
+#[rustc_diagnostic_item = "init"]
+fn init() {}
+
+#[rustc_diagnostic_item = "clippy_dataflow_peek_set"]
+fn clippy_dataflow_peek_set<T>(_: T) {}
+
+#[rustc_diagnostic_item = "clippy_dataflow_peek_unset"]
+fn clippy_dataflow_peek_unset<T>(_: T) {}
+
+fn main() {
+    clippy_dataflow_peek_unset(());
+    init();
+    clippy_dataflow_peek_set(());
+}